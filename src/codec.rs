@@ -0,0 +1,120 @@
+use std::time::Instant;
+
+use crate::parser::{Command, ParseError, Parser};
+
+/// Per-connection state that used to live ad hoc on `Server`: which tube
+/// `put`/`use` target, which tubes `reserve` watches, and whether the
+/// connection should be closed after its next reply.
+pub struct ConnState {
+    pub using: String,
+    pub watched: Vec<String>,
+    pub should_close: bool,
+    /// Command count and start of the current one-second window, used by
+    /// `Server`'s rate limiter to throttle connections that issue more
+    /// than `max_commands_per_sec`.
+    pub cmd_count: u32,
+    pub window_start: Instant,
+}
+
+impl ConnState {
+    pub fn new() -> ConnState {
+        ConnState {
+            using: "default".to_owned(),
+            watched: vec!["default".to_owned()],
+            should_close: false,
+            cmd_count: 0,
+            window_start: Instant::now(),
+        }
+    }
+}
+
+/// Typed responses to a command, independent of how they get written to
+/// the wire. `encode` is the only place that knows the beanstalkd text
+/// format.
+#[derive(Debug)]
+pub enum Reply {
+    Inserted(u64),
+    Reserved { id: u64, data: Vec<u8> },
+    Deleted,
+    Released,
+    NotFound,
+    Watching(usize),
+    Using(String),
+    TimedOut,
+    Ok(Vec<u8>),
+    NotFoundPeek,
+    BadFormat,
+}
+
+pub fn encode(reply: &Reply) -> Vec<u8> {
+    match reply {
+        Reply::Inserted(id) => format!("INSERTED {}\r\n", id).into_bytes(),
+        Reply::Reserved { id, data } => {
+            let mut out = format!("RESERVED {} {}\r\n", id, data.len()).into_bytes();
+            out.extend_from_slice(data);
+            out.extend_from_slice(b"\r\n");
+            out
+        }
+        Reply::Deleted => b"DELETED\r\n".to_vec(),
+        Reply::Released => b"RELEASED\r\n".to_vec(),
+        Reply::NotFound => b"NOT FOUND\r\n".to_vec(),
+        Reply::Watching(count) => format!("WATCHING {}\r\n", count).into_bytes(),
+        Reply::Using(tube) => format!("USING {}\r\n", tube).into_bytes(),
+        Reply::TimedOut => b"TIMED_OUT\r\n".to_vec(),
+        Reply::Ok(body) => {
+            let mut out = format!("OK {}\r\n", body.len()).into_bytes();
+            out.extend_from_slice(body);
+            out.extend_from_slice(b"\r\n");
+            out
+        }
+        Reply::NotFoundPeek => b"NOT_FOUND\r\n".to_vec(),
+        Reply::BadFormat => b"BAD_FORMAT\r\n".to_vec(),
+    }
+}
+
+/// Wraps the raw byte-buffer `Parser` together with the connection state
+/// it needs to decide how to respond, so `Server` no longer hand-manages
+/// buffer offsets and ad hoc fields itself.
+pub struct Codec {
+    parser: Parser,
+    pub conn: ConnState,
+}
+
+impl Codec {
+    pub fn new() -> Codec {
+        Codec {
+            parser: Parser::new(),
+            conn: ConnState::new(),
+        }
+    }
+
+    pub fn is_incomplete(&self) -> bool {
+        self.parser.is_incomplete()
+    }
+
+    pub fn allocate(&mut self) {
+        self.parser.allocate()
+    }
+
+    pub fn written(&self) -> usize {
+        self.parser.written
+    }
+
+    pub fn add_written(&mut self, n: usize) {
+        self.parser.written += n;
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        self.parser.get_mut()
+    }
+
+    /// Tries to decode the next pipelined command out of the buffer,
+    /// leaving any leftover bytes in place for the next call.
+    pub fn decode(&mut self) -> Result<Command, ParseError> {
+        self.parser.next()
+    }
+
+    pub fn encode(&self, reply: Reply) -> Vec<u8> {
+        encode(&reply)
+    }
+}
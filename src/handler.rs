@@ -0,0 +1,238 @@
+use crate::codec::{ConnState, Reply};
+use crate::jobqueue::JobQueue;
+use crate::limits::ConnStats;
+use crate::parser::Command;
+
+/// Applies a non-blocking command to `queue`/`conn` and returns the
+/// typed reply. Touches no I/O, so it can be table-driven tested without
+/// a real `TcpStream`.
+///
+/// `Command::Reserve`/`Command::ReserveWithTimeout` aren't handled here:
+/// they may need to block waiting for a job, which means parking without
+/// holding any of `queue`'s per-tube locks, so `Server` deals with those
+/// itself via `reserve_blocking` before falling back to this handler for
+/// everything else.
+pub fn handle(command: Command, conn: &mut ConnState, queue: &JobQueue, stats: &ConnStats) -> Reply {
+    queue.tick();
+
+    match command {
+        Command::Put { pri, delay, ttr, data } => {
+            let id = queue.put(&conn.using, pri, delay, ttr, data);
+            Reply::Inserted(id)
+        }
+        Command::Reserve | Command::ReserveWithTimeout { .. } => {
+            unreachable!("reserve commands are handled by Server::reserve_blocking")
+        }
+        Command::Delete { id } => match queue.delete(&id) {
+            Some(_) => Reply::Deleted,
+            None => Reply::NotFound,
+        },
+        Command::Release { id, pri, delay } => match queue.release(&id, pri, delay) {
+            Some(_) => Reply::Released,
+            None => Reply::NotFound,
+        },
+        Command::Watch { tube } => {
+            let tube = match String::from_utf8(tube) {
+                Ok(tube) => tube,
+                Err(_) => return Reply::BadFormat,
+            };
+            if !conn.watched.contains(&tube) {
+                conn.watched.push(tube);
+            }
+            Reply::Watching(conn.watched.len())
+        }
+        Command::ListTubes {} => Reply::Ok(b"default".to_vec()),
+        Command::StatsTube { tube } => {
+            let tube = match String::from_utf8(tube) {
+                Ok(tube) => tube,
+                Err(_) => return Reply::BadFormat,
+            };
+            let tube_stats = match queue.tube_stats(&tube) {
+                Some(tube_stats) => tube_stats,
+                None => return Reply::NotFoundPeek,
+            };
+
+            let rate_limit = match stats.max_commands_per_sec() {
+                Some(limit) => limit.to_string(),
+                None => "unlimited".to_owned(),
+            };
+            let body = format!(
+                "name: {}
+current-jobs-urgent: 0
+current-jobs-ready: {}
+current-jobs-reserved: {}
+current-jobs-delayed: {}
+current-jobs-buried: 0
+total-jobs: 0
+current-using: 0
+current-waiting: 0
+current-watching: 0
+pause: 0
+cmd-delete: 0
+cmd-pause-tube: 0
+pause-time-left: 0
+current-connections: {}
+max-connections: {}
+max-commands-per-sec: {}
+",
+                tube,
+                tube_stats.ready,
+                tube_stats.reserved,
+                tube_stats.delayed,
+                stats.current_connections(),
+                stats.max_connections(),
+                rate_limit,
+            );
+            Reply::Ok(body.into_bytes())
+        }
+        Command::UseTube { tube } => {
+            conn.using = match String::from_utf8(tube) {
+                Ok(tube) => tube,
+                Err(_) => return Reply::BadFormat,
+            };
+            Reply::Using(conn.using.clone())
+        }
+        Command::PeekReady {} => Reply::NotFoundPeek,
+        Command::PeekDelayed {} => Reply::NotFoundPeek,
+        Command::PeekBuried {} => Reply::NotFoundPeek,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limits;
+
+    fn test_stats() -> ConnStats {
+        ConnStats::new(Limits { max_connections: 1024, max_commands_per_sec: None })
+    }
+
+    /// One command issued against the shared `conn`/`queue` of its case,
+    /// with a predicate over the reply it should produce. Using a
+    /// function pointer rather than `Reply: PartialEq` keeps cases able
+    /// to assert on a reply's payload (e.g. capturing a `put`'s id for a
+    /// later step) without a derive on every `Reply` variant.
+    struct Step {
+        command: Command,
+        expect: fn(&Reply) -> bool,
+    }
+
+    struct Case {
+        name: &'static str,
+        steps: Vec<Step>,
+    }
+
+    fn run_cases(cases: Vec<Case>) {
+        for case in cases {
+            let mut conn = ConnState::new();
+            let queue = JobQueue::new();
+            let stats = test_stats();
+
+            for (i, step) in case.steps.into_iter().enumerate() {
+                let reply = handle(step.command, &mut conn, &queue, &stats);
+                assert!(
+                    (step.expect)(&reply),
+                    "case {:?}, step {}: unexpected reply {:?}",
+                    case.name,
+                    i,
+                    reply,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn protocol_cases() {
+        run_cases(vec![
+            Case {
+                name: "put then delete",
+                steps: vec![
+                    Step {
+                        command: Command::Put { pri: 1, delay: 0, ttr: 60, data: b"hi".to_vec() },
+                        expect: |r| matches!(r, Reply::Inserted(_)),
+                    },
+                    Step {
+                        command: Command::Delete { id: 1 },
+                        expect: |r| matches!(r, Reply::Deleted),
+                    },
+                    Step {
+                        // already deleted: not found, not a second DELETED
+                        command: Command::Delete { id: 1 },
+                        expect: |r| matches!(r, Reply::NotFound),
+                    },
+                ],
+            },
+            Case {
+                name: "delete before ever reserving still finds the job",
+                steps: vec![
+                    Step {
+                        command: Command::Put { pri: 1, delay: 0, ttr: 60, data: b"hi".to_vec() },
+                        expect: |r| matches!(r, Reply::Inserted(_)),
+                    },
+                    Step {
+                        command: Command::Delete { id: 1 },
+                        expect: |r| matches!(r, Reply::Deleted),
+                    },
+                ],
+            },
+            Case {
+                name: "use then watch",
+                steps: vec![
+                    Step {
+                        command: Command::UseTube { tube: b"jobs".to_vec() },
+                        expect: |r| matches!(r, Reply::Using(tube) if tube == "jobs"),
+                    },
+                    Step {
+                        command: Command::Watch { tube: b"jobs".to_vec() },
+                        // "default" plus "jobs"
+                        expect: |r| matches!(r, Reply::Watching(2)),
+                    },
+                ],
+            },
+            Case {
+                name: "release an unknown job is not found",
+                steps: vec![Step {
+                    command: Command::Release { id: 999, pri: 1, delay: 0 },
+                    expect: |r| matches!(r, Reply::NotFound),
+                }],
+            },
+            Case {
+                name: "watch with a non-utf8 tube is bad format",
+                steps: vec![Step {
+                    command: Command::Watch { tube: vec![0xff, 0xfe] },
+                    expect: |r| matches!(r, Reply::BadFormat),
+                }],
+            },
+            Case {
+                name: "stats-tube on an unreferenced tube is not found",
+                steps: vec![Step {
+                    command: Command::StatsTube { tube: b"never-used".to_vec() },
+                    expect: |r| matches!(r, Reply::NotFoundPeek),
+                }],
+            },
+            Case {
+                name: "stats-tube reports the requested tube's name and counts",
+                steps: vec![
+                    Step {
+                        command: Command::UseTube { tube: b"widgets".to_vec() },
+                        expect: |r| matches!(r, Reply::Using(_)),
+                    },
+                    Step {
+                        command: Command::Put { pri: 1, delay: 0, ttr: 60, data: b"hi".to_vec() },
+                        expect: |r| matches!(r, Reply::Inserted(_)),
+                    },
+                    Step {
+                        command: Command::StatsTube { tube: b"widgets".to_vec() },
+                        expect: |r| match r {
+                            Reply::Ok(body) => {
+                                let body = String::from_utf8_lossy(body);
+                                body.contains("name: widgets") && body.contains("current-jobs-ready: 1")
+                            }
+                            _ => false,
+                        },
+                    },
+                ],
+            },
+        ]);
+    }
+}
@@ -0,0 +1,532 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use crate::wal::{SyncPolicy, Wal, WalOp};
+
+pub type JobId = u64;
+pub type Tube = String;
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: JobId,
+    pub tube: Tube,
+    pub pri: u32,
+    pub ttr: u32,
+    pub data: Vec<u8>,
+}
+
+/// Entry in a tube's ready heap. Ordered so that `BinaryHeap::pop` (a
+/// max-heap) returns the lowest `pri` first, and ties break on the lowest
+/// id (i.e. whichever job was put first), matching beanstalkd's "most
+/// urgent first" contract.
+struct ReadyEntry(Job);
+
+impl PartialEq for ReadyEntry {
+    fn eq(&self, other: &ReadyEntry) -> bool {
+        self.0.pri == other.0.pri && self.0.id == other.0.id
+    }
+}
+impl Eq for ReadyEntry {}
+
+impl PartialOrd for ReadyEntry {
+    fn partial_cmp(&self, other: &ReadyEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ReadyEntry {
+    fn cmp(&self, other: &ReadyEntry) -> Ordering {
+        other
+            .0
+            .pri
+            .cmp(&self.0.pri)
+            .then_with(|| other.0.id.cmp(&self.0.id))
+    }
+}
+
+/// Entry in the delayed heap, ordered so the job with the soonest
+/// `ready_at` pops first.
+struct DelayedEntry {
+    ready_at: Instant,
+    job: Job,
+}
+
+impl PartialEq for DelayedEntry {
+    fn eq(&self, other: &DelayedEntry) -> bool {
+        self.ready_at == other.ready_at && self.job.id == other.job.id
+    }
+}
+impl Eq for DelayedEntry {}
+
+impl PartialOrd for DelayedEntry {
+    fn partial_cmp(&self, other: &DelayedEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DelayedEntry {
+    fn cmp(&self, other: &DelayedEntry) -> Ordering {
+        other.ready_at.cmp(&self.ready_at)
+    }
+}
+
+struct Reserved {
+    job: Job,
+    deadline: Instant,
+}
+
+/// A reserving connection parked on one or more watched tubes. Registered
+/// under every watched tube's waiter queue; whichever tube produces a job
+/// first takes the (shared) sender, so the other queues' copies become
+/// no-ops when they're eventually popped. `Arc<Mutex<_>>` rather than
+/// `Rc<RefCell<_>>` because `JobQueue` is shared across tasks spawned onto
+/// the tokio thread pool, which requires it (and everything it owns) to be
+/// `Send`.
+struct Waiter {
+    sender: Option<oneshot::Sender<(JobId, Vec<u8>)>>,
+}
+
+/// One tube's worth of queue state, behind its own lock. Splitting state
+/// out per tube (rather than one lock over the whole queue) means `put`s
+/// and `reserve`s against different tubes don't contend with each other.
+#[derive(Default)]
+struct TubeShard {
+    ready: BinaryHeap<ReadyEntry>,
+    delayed: BinaryHeap<DelayedEntry>,
+    reserved: HashMap<JobId, Reserved>,
+    waiters: VecDeque<Arc<Mutex<Waiter>>>,
+}
+
+impl TubeShard {
+    /// Moves a job straight to a live waiter if one is parked, otherwise
+    /// onto the ready heap. Returns the `Reserve` WAL record to log for a
+    /// handoff, if any — the caller logs it under the WAL's own lock, not
+    /// this shard's, so the two locks are never held at once.
+    fn make_ready(&mut self, job: Job) -> Option<WalOp> {
+        if let Some(waiter) = self.next_live_waiter() {
+            let data = job.data.clone();
+            let id = job.id;
+            let deadline = Instant::now() + Duration::from_secs(job.ttr as u64);
+            self.reserved.insert(id, Reserved { job, deadline });
+
+            let sender = waiter.lock().unwrap().sender.take().unwrap();
+            let _ = sender.send((id, data));
+            return Some(WalOp::Reserve { id });
+        }
+
+        self.ready.push(ReadyEntry(job));
+        None
+    }
+
+    /// Pops waiters until it finds one that's still live: not yet claimed
+    /// by another watched tube, and not abandoned by a
+    /// `reserve-with-timeout` that already gave up. A timed-out reserve
+    /// just drops its `Receiver` without ever taking the sender, so
+    /// `is_some()` alone can't tell a merely-parked waiter from a dead
+    /// one — checking `is_closed()` on the still-present sender can.
+    fn next_live_waiter(&mut self) -> Option<Arc<Mutex<Waiter>>> {
+        while let Some(waiter) = self.waiters.pop_front() {
+            let mut state = waiter.lock().unwrap();
+            match &state.sender {
+                Some(sender) if !sender.is_closed() => {
+                    drop(state);
+                    return Some(waiter);
+                }
+                Some(_) => state.sender = None,
+                None => {}
+            }
+        }
+        None
+    }
+
+    /// Promotes delayed jobs whose `ready_at` has passed into the ready
+    /// heap, and releases reserved jobs whose TTR has expired back to
+    /// ready. Returns any `Reserve` records produced by waiter handoffs
+    /// along the way.
+    fn tick(&mut self, now: Instant) -> Vec<WalOp> {
+        let mut wal_ops = Vec::new();
+
+        let mut newly_ready = Vec::new();
+        while let Some(entry) = self.delayed.peek() {
+            if entry.ready_at > now {
+                break;
+            }
+            newly_ready.push(self.delayed.pop().unwrap().job);
+        }
+        for job in newly_ready {
+            wal_ops.extend(self.make_ready(job));
+        }
+
+        let expired: Vec<JobId> = self
+            .reserved
+            .iter()
+            .filter(|(_, r)| r.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            if let Some(reserved) = self.reserved.remove(&id) {
+                wal_ops.extend(self.make_ready(reserved.job));
+            }
+        }
+
+        wal_ops
+    }
+
+    fn peek_best(&self) -> Option<(u32, JobId)> {
+        self.ready.peek().map(|entry| (entry.0.pri, entry.0.id))
+    }
+
+    /// Removes a job from whichever of `reserved`/`ready`/`delayed` it
+    /// currently lives in, returning it if found. A job can be deleted at
+    /// any point in its lifecycle, not just after being reserved.
+    fn remove_job(&mut self, id: JobId) -> Option<Job> {
+        if let Some(reserved) = self.reserved.remove(&id) {
+            return Some(reserved.job);
+        }
+
+        self.remove_ready(id).or_else(|| self.remove_delayed(id))
+    }
+
+    fn remove_ready(&mut self, id: JobId) -> Option<Job> {
+        let items = std::mem::take(&mut self.ready).into_vec();
+        let mut found = None;
+        let mut remaining = Vec::with_capacity(items.len());
+        for entry in items {
+            if found.is_none() && entry.0.id == id {
+                found = Some(entry.0);
+            } else {
+                remaining.push(entry);
+            }
+        }
+        for entry in remaining {
+            self.ready.push(entry);
+        }
+        found
+    }
+
+    fn remove_delayed(&mut self, id: JobId) -> Option<Job> {
+        let items = std::mem::take(&mut self.delayed).into_vec();
+        let mut found = None;
+        let mut remaining = Vec::with_capacity(items.len());
+        for entry in items {
+            if found.is_none() && entry.job.id == id {
+                found = Some(entry.job);
+            } else {
+                remaining.push(entry);
+            }
+        }
+        for entry in remaining {
+            self.delayed.push(entry);
+        }
+        found
+    }
+}
+
+/// Per-tube stats reported by `stats-tube`.
+pub struct TubeStats {
+    pub ready: usize,
+    pub delayed: usize,
+    pub reserved: usize,
+}
+
+/// Sharded by tube so commands against different tubes don't serialize on
+/// one lock: every method takes `&self` and reaches into the relevant
+/// `TubeShard`'s own `Mutex` (plus, for cross-tube bookkeeping, the small
+/// `shards`/`job_tube`/`wal` locks below, each held only for the
+/// duration of a single map lookup or log append). `JobQueue` itself can
+/// therefore be shared across connection tasks as a plain `Arc<JobQueue>`,
+/// with no outer queue-wide lock for every command to queue behind.
+pub struct JobQueue {
+    next_id: AtomicU64,
+    shards: Mutex<HashMap<Tube, Arc<Mutex<TubeShard>>>>,
+    /// Which tube a live job belongs to, so `delete`/`release` can find
+    /// its shard without the caller needing to know the tube up front.
+    job_tube: Mutex<HashMap<JobId, Tube>>,
+    wal: Option<Mutex<Wal>>,
+}
+
+impl JobQueue {
+    pub fn new() -> JobQueue {
+        // "default" always exists for stats-tube purposes, matching every
+        // connection's initial `using`/`watched` state, even before any
+        // job has ever targeted it.
+        let mut shards = HashMap::new();
+        shards.insert("default".to_owned(), Arc::new(Mutex::new(TubeShard::default())));
+
+        JobQueue {
+            next_id: AtomicU64::new(1),
+            shards: Mutex::new(shards),
+            job_tube: Mutex::new(HashMap::new()),
+            wal: None,
+        }
+    }
+
+    /// Replays `<data_dir>/beanstalkdrs.log` (if it exists) into a fresh
+    /// queue, then keeps appending to it so a later restart can recover
+    /// again.
+    pub fn open(data_dir: &Path, sync_policy: SyncPolicy) -> io::Result<JobQueue> {
+        std::fs::create_dir_all(data_dir)?;
+        let log_path = data_dir.join("beanstalkdrs.log");
+
+        let mut queue = JobQueue::new();
+        for op in Wal::replay(&log_path)? {
+            queue.apply_replayed(op);
+        }
+
+        queue.wal = Some(Mutex::new(Wal::open(&log_path, sync_policy)?));
+        Ok(queue)
+    }
+
+    fn apply_replayed(&mut self, op: WalOp) {
+        match op {
+            WalOp::Put { id, tube, pri, delay, ttr, data } => {
+                self.insert_job(id, tube, pri, ttr, delay, data);
+            }
+            WalOp::Reserve { id } => self.reserve_specific_for_replay(id),
+            WalOp::Delete { id } => {
+                if let Some(tube) = self.job_tube.lock().unwrap().remove(&id) {
+                    self.shard(&tube).lock().unwrap().reserved.remove(&id);
+                }
+            }
+            WalOp::Release { id, pri, delay } => {
+                let tube = match self.job_tube.lock().unwrap().get(&id).cloned() {
+                    Some(tube) => tube,
+                    None => return,
+                };
+                let shard = self.shard(&tube);
+                let reserved = shard.lock().unwrap().reserved.remove(&id);
+                if let Some(mut reserved) = reserved {
+                    reserved.job.pri = pri;
+                    if delay == 0 {
+                        shard.lock().unwrap().make_ready(reserved.job);
+                    } else {
+                        let ready_at = Instant::now() + Duration::from_secs(delay as u64);
+                        shard.lock().unwrap().delayed.push(DelayedEntry { ready_at, job: reserved.job });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves the job with `id` out of whichever tube's ready heap holds
+    /// it and into that tube's reserved set, used only to replay a
+    /// `Reserve` record exactly (as opposed to `reserve`'s "best job"
+    /// search across watched tubes).
+    fn reserve_specific_for_replay(&mut self, id: JobId) {
+        let shards: Vec<Arc<Mutex<TubeShard>>> = self.shards.lock().unwrap().values().cloned().collect();
+        for shard in shards {
+            let mut shard = shard.lock().unwrap();
+            if let Some(job) = shard.remove_ready(id) {
+                let deadline = Instant::now() + Duration::from_secs(job.ttr as u64);
+                shard.reserved.insert(id, Reserved { job, deadline });
+                return;
+            }
+        }
+    }
+
+    /// Writes a still-live snapshot of the queue to the log, dropping the
+    /// history that produced it. No-op when durability isn't enabled.
+    pub fn compact(&self) -> io::Result<()> {
+        let now = Instant::now();
+        let mut live: Vec<(Job, u32)> = Vec::new();
+
+        let shards: Vec<Arc<Mutex<TubeShard>>> = self.shards.lock().unwrap().values().cloned().collect();
+        for shard in shards {
+            let shard = shard.lock().unwrap();
+            live.extend(shard.ready.iter().map(|entry| (entry.0.clone(), 0)));
+            for entry in shard.delayed.iter() {
+                let remaining = entry.ready_at.saturating_duration_since(now).as_secs() as u32;
+                live.push((entry.job.clone(), remaining));
+            }
+            for reserved in shard.reserved.values() {
+                live.push((reserved.job.clone(), 0));
+            }
+        }
+
+        match &self.wal {
+            Some(wal) => wal.lock().unwrap().compact(&live),
+            None => Ok(()),
+        }
+    }
+
+    fn shard(&self, tube: &str) -> Arc<Mutex<TubeShard>> {
+        let mut shards = self.shards.lock().unwrap();
+        shards.entry(tube.to_owned()).or_default().clone()
+    }
+
+    fn log(&self, op: &WalOp) {
+        if let Some(wal) = &self.wal {
+            let _ = wal.lock().unwrap().append(op);
+        }
+    }
+
+    /// Inserts a job, honoring `pri`/`delay`/`ttr`. Returns the new job id.
+    ///
+    /// Logs the `Put` record *before* calling `insert_job`: a `delay == 0`
+    /// job can be handed straight to a parked waiter, and `insert_job`
+    /// appends its own `Reserve` record for that handoff. Logging after
+    /// `insert_job` would let that `Reserve` land in the log ahead of this
+    /// job's own `Put`, so replay would find nothing to reserve, no-op it,
+    /// then re-insert the job as ready — a job already delivered to a
+    /// client would come back after a crash and be handed out again.
+    pub fn put(&self, tube: &str, pri: u32, delay: u32, ttr: u32, data: Vec<u8>) -> JobId {
+        let id = self.next_id.fetch_add(1, AtomicOrdering::SeqCst);
+
+        self.log(&WalOp::Put {
+            id,
+            tube: tube.to_owned(),
+            pri,
+            delay,
+            ttr,
+            data: data.clone(),
+        });
+
+        self.insert_job(id, tube.to_owned(), pri, ttr, delay, data);
+
+        id
+    }
+
+    /// Raw state mutation shared by `put` and WAL replay: place a job
+    /// with a known id into its tube's ready or delayed set, bumping
+    /// `next_id` so ids never collide regardless of which path assigned
+    /// them.
+    fn insert_job(&self, id: JobId, tube: Tube, pri: u32, ttr: u32, delay: u32, data: Vec<u8>) {
+        self.next_id.fetch_max(id + 1, AtomicOrdering::SeqCst);
+        self.job_tube.lock().unwrap().insert(id, tube.clone());
+
+        let shard = self.shard(&tube);
+        let job = Job { id, tube, pri, ttr, data };
+
+        if delay == 0 {
+            let wal_op = shard.lock().unwrap().make_ready(job);
+            if let Some(op) = wal_op {
+                self.log(&op);
+            }
+        } else {
+            let ready_at = Instant::now() + Duration::from_secs(delay as u64);
+            shard.lock().unwrap().delayed.push(DelayedEntry { ready_at, job });
+        }
+    }
+
+    /// Promotes delayed jobs whose `ready_at` has passed into the ready
+    /// heap, and releases reserved jobs whose TTR has expired back to
+    /// ready, across every tube. Should be called periodically (or on
+    /// every command) so timing semantics hold even on an idle queue.
+    pub fn tick(&self) {
+        let now = Instant::now();
+        let shards: Vec<Arc<Mutex<TubeShard>>> = self.shards.lock().unwrap().values().cloned().collect();
+        for shard in shards {
+            let wal_ops = shard.lock().unwrap().tick(now);
+            for op in wal_ops {
+                self.log(&op);
+            }
+        }
+    }
+
+    /// Pops the most urgent ready job across `tubes`, if any, and moves it
+    /// into the reserved set with a TTR deadline.
+    pub fn reserve(&self, tubes: &[String]) -> Option<(JobId, Vec<u8>)> {
+        self.tick();
+
+        // Peek (without removing) the best candidate in each watched
+        // tube's shard, then pop only from the shard that wins. If
+        // another reserver beat us to it in the meantime, the heap came
+        // up empty on pop; retry against whatever's left rather than
+        // returning early.
+        loop {
+            let best_tube = tubes
+                .iter()
+                .filter_map(|tube| {
+                    let shard = self.shard(tube);
+                    let best = shard.lock().unwrap().peek_best();
+                    best.map(|(pri, id)| (tube.clone(), pri, id))
+                })
+                .min_by(|a, b| a.1.cmp(&b.1).then_with(|| a.2.cmp(&b.2)))
+                .map(|(tube, _, _)| tube)?;
+
+            let shard = self.shard(&best_tube);
+            let job = match shard.lock().unwrap().ready.pop() {
+                Some(entry) => entry.0,
+                None => continue,
+            };
+
+            let id = job.id;
+            let data = job.data.clone();
+            let deadline = Instant::now() + Duration::from_secs(job.ttr as u64);
+            shard.lock().unwrap().reserved.insert(id, Reserved { job, deadline });
+
+            self.log(&WalOp::Reserve { id });
+
+            return Some((id, data));
+        }
+    }
+
+    /// Registers a blocking reserver across `tubes`. Returns a receiver
+    /// that resolves once any of those tubes produces a job for it.
+    pub fn register_waiter(&self, tubes: &[String]) -> oneshot::Receiver<(JobId, Vec<u8>)> {
+        let (tx, rx) = oneshot::channel();
+        let waiter = Arc::new(Mutex::new(Waiter { sender: Some(tx) }));
+
+        for tube in tubes {
+            self.shard(tube).lock().unwrap().waiters.push_back(waiter.clone());
+        }
+
+        rx
+    }
+
+    pub fn delete(&self, id: &JobId) -> Option<()> {
+        let tube = self.job_tube.lock().unwrap().get(id).cloned()?;
+        let found = self.shard(&tube).lock().unwrap().remove_job(*id).is_some();
+        if !found {
+            return None;
+        }
+
+        self.job_tube.lock().unwrap().remove(id);
+        self.log(&WalOp::Delete { id: *id });
+        Some(())
+    }
+
+    /// Puts a reserved job back onto its tube's ready (or delayed) heap,
+    /// honoring the client-supplied `pri`/`delay` overrides for the
+    /// release rather than keeping the job's original ones.
+    pub fn release(&self, id: &JobId, pri: u32, delay: u32) -> Option<()> {
+        let tube = self.job_tube.lock().unwrap().get(id).cloned()?;
+        let shard = self.shard(&tube);
+        let mut reserved = shard.lock().unwrap().reserved.remove(id)?;
+        reserved.job.pri = pri;
+
+        self.log(&WalOp::Release { id: *id, pri, delay });
+
+        if delay == 0 {
+            let wal_op = shard.lock().unwrap().make_ready(reserved.job);
+            if let Some(op) = wal_op {
+                self.log(&op);
+            }
+        } else {
+            let ready_at = Instant::now() + Duration::from_secs(delay as u64);
+            shard.lock().unwrap().delayed.push(DelayedEntry { ready_at, job: reserved.job });
+        }
+
+        Some(())
+    }
+
+    /// Live counts for `stats-tube`, or `None` if the tube has never been
+    /// referenced by a `put`/`use`/`watch`.
+    pub fn tube_stats(&self, tube: &str) -> Option<TubeStats> {
+        let shards = self.shards.lock().unwrap();
+        let shard = shards.get(tube)?.lock().unwrap();
+        Some(TubeStats {
+            ready: shard.ready.len(),
+            delayed: shard.delayed.len(),
+            reserved: shard.reserved.len(),
+        })
+    }
+}
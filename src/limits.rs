@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Server-wide connection caps, loaded once at startup from the
+/// environment (see `load_limits_config` in `main.rs`).
+pub struct Limits {
+    pub max_connections: usize,
+    pub max_commands_per_sec: Option<u32>,
+}
+
+/// Shared handle the accept loop and the command handler both hold: the
+/// accept loop acquires a permit per connection (blocking once
+/// `max_connections` are already in flight, so excess connections queue
+/// rather than spawning unbounded handlers), and `stats-tube` reads the
+/// current/limit counts back out for operators.
+pub struct ConnStats {
+    limits: Limits,
+    semaphore: Arc<Semaphore>,
+}
+
+impl ConnStats {
+    pub fn new(limits: Limits) -> ConnStats {
+        let semaphore = Arc::new(Semaphore::new(limits.max_connections));
+        ConnStats { limits, semaphore }
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.limits.max_connections
+    }
+
+    pub fn max_commands_per_sec(&self) -> Option<u32> {
+        self.limits.max_commands_per_sec
+    }
+
+    /// Derived from the semaphore's available permits rather than a
+    /// separate counter, so there's only one source of truth for "how
+    /// many connections are in flight right now".
+    pub fn current_connections(&self) -> usize {
+        self.limits.max_connections - self.semaphore.available_permits()
+    }
+
+    /// Blocks until a connection slot is free, then returns a permit that
+    /// holds the slot for as long as the caller keeps it alive.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("connection semaphore never closes")
+    }
+}
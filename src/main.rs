@@ -1,53 +1,141 @@
-#[macro_use]
-extern crate nom;
-
 mod parser;
 
-use parser::*;
+use crate::parser::*;
+
+mod codec;
+
+use crate::codec::{Codec, Reply};
+
+mod handler;
 
 mod jobqueue;
 
-use jobqueue::*;
+use crate::jobqueue::*;
+
+mod transport;
+
+use crate::transport::{SecureConfig, Transport};
+
+mod wal;
+
+use crate::wal::SyncPolicy;
+
+mod limits;
 
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::str;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use crate::limits::{ConnStats, Limits};
 
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::net::TcpListener;
+use tokio::time::{interval, sleep, timeout, Duration};
+
+/// How many ticks between log compactions; at the ticker's 100ms period
+/// this is roughly once a minute.
+const COMPACT_EVERY_TICKS: u32 = 600;
+
+/// One task per connection, sharing `job_queue` as a plain `Arc<JobQueue>`
+/// with no outer lock: `JobQueue` shards its state by tube internally
+/// (see `jobqueue::TubeShard`), so commands against different tubes no
+/// longer serialize on one process-wide mutex.
 struct Server {
-    stream: TcpStream,
-    job_queue: Arc<Mutex<JobQueue>>,
+    stream: Transport,
+    job_queue: Arc<JobQueue>,
+    codec: Codec,
+    conn_stats: Arc<ConnStats>,
 }
 
 impl Server {
-    fn new(stream: TcpStream, job_queue: Arc<Mutex<JobQueue>>) -> Server {
+    fn new(stream: Transport, job_queue: Arc<JobQueue>, conn_stats: Arc<ConnStats>) -> Server {
         Server {
-            stream: stream,
-            job_queue: job_queue,
+            stream,
+            job_queue,
+            codec: Codec::new(),
+            conn_stats,
+        }
+    }
+
+    /// Throttles this connection when `max_commands_per_sec` is set: once
+    /// the current one-second window's count is exceeded, sleeps out the
+    /// rest of the window before letting the command through.
+    async fn throttle(&mut self) {
+        let limit = match self.conn_stats.max_commands_per_sec() {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        let conn = &mut self.codec.conn;
+        let now = Instant::now();
+        if now.duration_since(conn.window_start) >= Duration::from_secs(1) {
+            conn.window_start = now;
+            conn.cmd_count = 0;
+        }
+
+        conn.cmd_count += 1;
+        if conn.cmd_count > limit {
+            let remaining = Duration::from_secs(1) - now.duration_since(conn.window_start);
+            sleep(remaining).await;
+            conn.window_start = Instant::now();
+            conn.cmd_count = 1;
         }
     }
 
-    fn run(&mut self) {
-        let mut parser = Parser::new();
+    /// Reserves a job from the connection's watched tubes, parking the
+    /// task (without holding any queue lock, and without spinning a
+    /// thread) until one becomes available or, if `timeout_dur` is set,
+    /// the deadline passes.
+    async fn reserve_blocking(&self, timeout_dur: Option<Duration>) -> Option<(JobId, Vec<u8>)> {
+        if let Some(result) = self.job_queue.reserve(&self.codec.conn.watched) {
+            return Some(result);
+        }
+        let rx = self.job_queue.register_waiter(&self.codec.conn.watched);
+
+        match timeout_dur {
+            Some(duration) => match timeout(duration, rx).await {
+                Ok(Ok(result)) => Some(result),
+                Ok(Err(_)) | Err(_) => None,
+            },
+            None => rx.await.ok(),
+        }
+    }
+
+    /// Routes a decoded command to a reply. `Reserve`/`ReserveWithTimeout`
+    /// may need to block, so they're handled here, outside the queue
+    /// lock; everything else goes through the pure, testable
+    /// `handler::handle`.
+    async fn handle(&mut self, command: Command) -> Reply {
+        match command {
+            Command::Reserve => match self.reserve_blocking(None).await {
+                Some((id, data)) => Reply::Reserved { id, data },
+                None => Reply::TimedOut,
+            },
+            Command::ReserveWithTimeout { timeout: timeout_secs } => {
+                let deadline = Duration::from_secs(timeout_secs as u64);
+                match self.reserve_blocking(Some(deadline)).await {
+                    Some((id, data)) => Reply::Reserved { id, data },
+                    None => Reply::TimedOut,
+                }
+            },
+            command => handler::handle(command, &mut self.codec.conn, &self.job_queue, &self.conn_stats),
+        }
+    }
 
+    async fn run(&mut self) {
         loop {
-            if parser.is_incomplete() {
-                parser.allocate();
-                let len = {
-                    let pos = parser.written;
-                    let mut buffer = parser.get_mut();
-
-                    // read socket
-                    match self.stream.read(&mut buffer[pos..]) {
-                        Ok(r) => r,
-                        Err(err) => {
-                            println!("Reading from client: {:?}", err);
-                            break;
-                        }
+            if self.codec.is_incomplete() {
+                self.codec.allocate();
+                let pos = self.codec.written();
+                let buffer = self.codec.buffer_mut();
+
+                let len = match self.stream.read(&mut buffer[pos..]).await {
+                    Ok(r) => r,
+                    Err(err) => {
+                        println!("Reading from client: {:?}", err);
+                        break;
                     }
                 };
-                parser.written += len;
+                self.codec.add_written(len);
 
                 // client closed connection
                 if len == 0 {
@@ -56,100 +144,14 @@ impl Server {
                 }
             }
 
-            match parser.next() {
+            match self.codec.decode() {
                 Ok(command) => {
                     println!("Received command {:?}", command);
 
-                    let mut job_queue = self.job_queue.lock().unwrap();
-
-                    match command {
-                        Command::Put {data} => {
-                            let mut alloc_data = Vec::new();
-                            alloc_data.extend_from_slice(data);
-
-                            let id = job_queue.put(1, 1, 1, alloc_data);
-
-                            let response = format!("INSERTED {}\r\n", id);
-
-                            self.stream.write(response.as_bytes());
-                        },
-                        Command::Reserve => {
-                            let (job_id, job_data) = job_queue.reserve();
-
-                            let header = format!("RESERVED {} {}\r\n", job_id, job_data.len());
-
-                            self.stream.write(header.as_bytes());
-                            self.stream.write(job_data.as_slice());
-                            self.stream.write(b"\r\n");
-                        },
-                        Command::Delete {id} => {
-                            let id = str::from_utf8(id)
-                                .unwrap()
-                                .parse::<u8>()
-                                .unwrap();
-
-                            match job_queue.delete(&id) {
-                                Some(_) => self.stream.write(b"DELETED\r\n"),
-                                None => self.stream.write(b"NOT FOUND\r\n"),
-                            };
-                        },
-                        Command::Release {id, pri, delay} => {
-                            let id = str::from_utf8(id)
-                                .unwrap()
-                                .parse::<u8>()
-                                .unwrap();
-
-                            match job_queue.release(&id) {
-                                Some(_) => self.stream.write(b"RELEASED\r\n"),
-                                None => self.stream.write(b"NOT FOUND\r\n"),
-                            };
-                        },
-                        Command::Watch {tube} => {
-                            self.stream.write(b"WATCHING 1\r\n");
-                        },
-                        Command::ListTubes {} => {
-                            let tube_list = "default";
-                            self.stream.write(format!(
-                                "OK {}\r\n{}\r\n",
-                                tube_list.len(),
-                                tube_list
-                            ).as_bytes());
-                        },
-                        Command::StatsTube {tube} => {
-                            let stats = "name: default
-current-jobs-urgent: 0
-current-jobs-ready: 0
-current-jobs-reserved: 0
-current-jobs-delayed: 0
-current-jobs-buried: 0
-total-jobs: 0
-current-using: 0
-current-waiting: 0
-current-watching: 0
-pause: 0
-cmd-delete: 0
-cmd-pause-tube: 0
-pause-time-left: 0
-";
-                            self.stream.write(format!(
-                                "OK {}\r\n{}\r\n",
-                                stats.len(),
-                                stats
-                            ).as_bytes());
-                        },
-                        Command::UseTube {tube} => {
-                            self.stream.write(format!("USING {:?}\r\n", tube).as_bytes());
-                        },
-                        Command::PeekReady {} => {
-                            self.stream.write(b"NOT_FOUND\r\n");
-                        },
-                        Command::PeekDelayed {} => {
-                            self.stream.write(b"NOT_FOUND\r\n");
-                        },
-                        Command::PeekBuried {} => {
-                            self.stream.write(b"NOT_FOUND\r\n");
-                        },
-                    };
+                    self.throttle().await;
+                    let reply = self.handle(command).await;
+                    let bytes = self.codec.encode(reply);
+                    let _ = self.stream.write_all(bytes.as_slice()).await;
                 },
                 Err(err) => {
                     match err {
@@ -165,27 +167,164 @@ pause-time-left: 0
                     }
                 }
             };
+
+            if self.codec.conn.should_close {
+                break;
+            }
+        }
+    }
+}
+
+/// Drives the scheduler tick on its own so delayed jobs get promoted and
+/// expired reservations get released even when no client is actively
+/// issuing commands. Also periodically compacts the write-ahead log (a
+/// no-op when durability isn't enabled).
+async fn run_ticker(job_queue: Arc<JobQueue>) {
+    let mut ticker = interval(Duration::from_millis(100));
+    let mut ticks = 0u32;
+    loop {
+        ticker.tick().await;
+        job_queue.tick();
+
+        ticks += 1;
+        if ticks.is_multiple_of(COMPACT_EVERY_TICKS) {
+            if let Err(err) = job_queue.compact() {
+                println!("WAL compaction failed: {:?}", err);
+            }
         }
     }
 }
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:11300").unwrap();
+/// Loads the listen address from the environment. Defaults to stock
+/// beanstalkd's port; an end-to-end test harness can set this to
+/// `127.0.0.1:0` to bind an ephemeral port instead of fighting other
+/// processes (or parallel test runs) over a fixed one.
+const DEFAULT_ADDR: &str = "127.0.0.1:11300";
 
-    let job_queue = Arc::new(Mutex::new(JobQueue::new()));
+fn load_addr_config() -> String {
+    std::env::var("BEANSTALKDRS_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_owned())
+}
 
-    for stream in listener.incoming() {
-        match stream {
-            Err(_) => panic!("error listen"),
-            Ok(stream) => {
-                let job_queue = job_queue.clone();
-                thread::spawn(move || {
-                    println!("client connected");
+/// Loads connection limits from the environment.
+/// `BEANSTALKDRS_MAX_CONNECTIONS` defaults to 1024; unset
+/// `BEANSTALKDRS_MAX_COMMANDS_PER_SEC` leaves per-connection command rate
+/// unthrottled, matching stock beanstalkd.
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
 
-                    let mut server = Server::new(stream, job_queue);
-                    server.run();
-                });
-            },
+fn load_limits_config() -> Limits {
+    let max_connections = std::env::var("BEANSTALKDRS_MAX_CONNECTIONS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CONNECTIONS);
+
+    let max_commands_per_sec = std::env::var("BEANSTALKDRS_MAX_COMMANDS_PER_SEC")
+        .ok()
+        .and_then(|v| v.parse().ok());
+
+    Limits { max_connections, max_commands_per_sec }
+}
+
+/// Loads the write-ahead log config from the environment.
+/// `BEANSTALKDRS_DATA_DIR` turns on durability; unset, the queue stays
+/// in-memory only, matching the original behavior.
+fn load_wal_config() -> Option<(PathBuf, SyncPolicy)> {
+    let data_dir = std::env::var("BEANSTALKDRS_DATA_DIR").ok()?;
+
+    let sync_policy = match std::env::var("BEANSTALKDRS_SYNC_POLICY").as_deref() {
+        Ok("never") => SyncPolicy::Never,
+        _ => SyncPolicy::Always,
+    };
+
+    Some((PathBuf::from(data_dir), sync_policy))
+}
+
+/// Loads the Secret-Handshake transport config from the environment.
+/// Both `BEANSTALKDRS_NETWORK_KEY` (32 bytes, hex) and
+/// `BEANSTALKDRS_SERVER_SEED` (ed25519 seed, hex) must be set to enable
+/// it; otherwise the server stays on plaintext, matching stock beanstalkd.
+fn load_secure_config() -> Option<SecureConfig> {
+    let network_key = hex_env("BEANSTALKDRS_NETWORK_KEY")?;
+    let server_seed = hex_env("BEANSTALKDRS_SERVER_SEED")?;
+    let identity = transport::ServerIdentity::from_seed(&server_seed);
+
+    Some(SecureConfig {
+        network_key,
+        identity,
+    })
+}
+
+fn hex_env(name: &str) -> Option<[u8; 32]> {
+    let value = std::env::var(name).ok()?;
+    let bytes = hex_decode(&value)?;
+    let mut out = [0u8; 32];
+    if bytes.len() != out.len() {
+        return None;
+    }
+    out.copy_from_slice(&bytes);
+    Some(out)
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[tokio::main]
+async fn main() {
+    let listener = TcpListener::bind(load_addr_config()).await.unwrap();
+    // First line of stdout on purpose: an end-to-end test harness spawns
+    // this binary with BEANSTALKDRS_ADDR=127.0.0.1:0 and parses this line
+    // to learn which port the OS actually handed out.
+    println!("listening on {}", listener.local_addr().unwrap());
+
+    let job_queue = match load_wal_config() {
+        Some((data_dir, sync_policy)) => JobQueue::open(&data_dir, sync_policy)
+            .expect("failed to open/replay write-ahead log"),
+        None => JobQueue::new(),
+    };
+    let job_queue = Arc::new(job_queue);
+    let secure_config = Arc::new(load_secure_config());
+    let conn_stats = Arc::new(ConnStats::new(load_limits_config()));
+
+    tokio::spawn(run_ticker(job_queue.clone()));
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(err) => {
+                println!("error accepting connection: {:?}", err);
+                continue;
+            }
         };
+
+        // Blocks here (not inside the spawned task) once
+        // `max_connections` are already in flight, so excess connections
+        // queue at the accept loop instead of spawning unbounded handlers.
+        let permit = conn_stats.acquire().await;
+
+        let job_queue = job_queue.clone();
+        let secure_config = secure_config.clone();
+        let conn_stats = conn_stats.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            let stream = match Transport::accept(stream, &secure_config).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("handshake failed: {:?}", err);
+                    return;
+                }
+            };
+
+            println!("client connected");
+
+            let mut server = Server::new(stream, job_queue, conn_stats);
+            server.run().await;
+        });
     }
 }
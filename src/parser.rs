@@ -0,0 +1,204 @@
+use std::str::{self, FromStr};
+
+/// Caps `put`'s client-supplied `bytes` field, both as a sanity limit
+/// (matching stock beanstalkd's default `max-job-size`) and to keep the
+/// `body_start + bytes` arithmetic below `usize` overflow.
+const MAX_JOB_SIZE: usize = 65_535;
+
+/// Parses beanstalkd protocol commands out of a growable byte buffer.
+///
+/// The buffer is reused across reads: `allocate()` grows it when there is no
+/// room left, `get_mut()` hands the writable tail to the socket read, and
+/// `next()` tries to pull one complete command out of whatever has been
+/// written so far, leaving any leftover (pipelined) bytes in place for the
+/// next call.
+pub struct Parser {
+    buf: Vec<u8>,
+    pub written: usize,
+    parsed: usize,
+}
+
+const INITIAL_CAPACITY: usize = 4096;
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser {
+            buf: vec![0; INITIAL_CAPACITY],
+            written: 0,
+            parsed: 0,
+        }
+    }
+
+    /// Grows the buffer when the unwritten tail gets too small, and
+    /// compacts already-parsed bytes off the front so the buffer doesn't
+    /// grow without bound on a long-lived, pipelining connection.
+    pub fn allocate(&mut self) {
+        if self.parsed > 0 {
+            self.buf.drain(0..self.parsed);
+            self.written -= self.parsed;
+            self.parsed = 0;
+        }
+
+        if self.buf.len() - self.written < INITIAL_CAPACITY {
+            self.buf.resize(self.buf.len() + INITIAL_CAPACITY, 0);
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    /// True once we need more bytes from the socket before `next()` can
+    /// make progress (i.e. there's no unparsed data left to try).
+    pub fn is_incomplete(&self) -> bool {
+        self.parsed >= self.written
+    }
+
+    /// Tries to parse the next pipelined command out of the buffer.
+    pub fn next(&mut self) -> Result<Command, ParseError> {
+        let available = &self.buf[self.parsed..self.written];
+
+        let line_end = match find_crlf(available) {
+            Some(pos) => pos,
+            None => return Err(ParseError::Incomplete),
+        };
+
+        let line = &available[..line_end];
+        let mut parts = line.split(|&b| b == b' ').filter(|s| !s.is_empty());
+
+        let name = parts.next().ok_or(ParseError::UnknownCommand)?;
+
+        match name {
+            b"put" => {
+                let pri = next_parsed(&mut parts)?;
+                let delay = next_parsed(&mut parts)?;
+                let ttr = next_parsed(&mut parts)?;
+                let bytes: usize = next_parsed(&mut parts)?;
+
+                if bytes > MAX_JOB_SIZE {
+                    return Err(ParseError::BadFormat);
+                }
+
+                let body_start = self.parsed + line_end + 2;
+                let body_end = body_start + bytes;
+                let total_end = body_end + 2; // trailing \r\n after the body
+
+                if self.written < total_end {
+                    return Err(ParseError::Incomplete);
+                }
+
+                let data = self.buf[body_start..body_end].to_vec();
+                self.parsed = total_end;
+
+                Ok(Command::Put { pri, delay, ttr, data })
+            }
+            b"reserve" => {
+                self.parsed += line_end + 2;
+                Ok(Command::Reserve)
+            }
+            b"reserve-with-timeout" => {
+                let timeout = next_parsed(&mut parts)?;
+                self.parsed += line_end + 2;
+                Ok(Command::ReserveWithTimeout { timeout })
+            }
+            b"delete" => {
+                let id = next_parsed(&mut parts)?;
+                self.parsed += line_end + 2;
+                Ok(Command::Delete { id })
+            }
+            b"release" => {
+                let id = next_parsed(&mut parts)?;
+                let pri = next_parsed(&mut parts)?;
+                let delay = next_parsed(&mut parts)?;
+                self.parsed += line_end + 2;
+                Ok(Command::Release { id, pri, delay })
+            }
+            b"watch" => {
+                let tube = parts.next().ok_or(ParseError::BadFormat)?.to_vec();
+                self.parsed += line_end + 2;
+                Ok(Command::Watch { tube })
+            }
+            b"use" => {
+                let tube = parts.next().ok_or(ParseError::BadFormat)?.to_vec();
+                self.parsed += line_end + 2;
+                Ok(Command::UseTube { tube })
+            }
+            b"list-tubes" => {
+                self.parsed += line_end + 2;
+                Ok(Command::ListTubes {})
+            }
+            b"stats-tube" => {
+                let tube = parts.next().ok_or(ParseError::BadFormat)?.to_vec();
+                self.parsed += line_end + 2;
+                Ok(Command::StatsTube { tube })
+            }
+            b"peek-ready" => {
+                self.parsed += line_end + 2;
+                Ok(Command::PeekReady {})
+            }
+            b"peek-delayed" => {
+                self.parsed += line_end + 2;
+                Ok(Command::PeekDelayed {})
+            }
+            b"peek-buried" => {
+                self.parsed += line_end + 2;
+                Ok(Command::PeekBuried {})
+            }
+            _ => Err(ParseError::UnknownCommand),
+        }
+    }
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+fn next_parsed<'a, T: FromStr, I: Iterator<Item = &'a [u8]>>(parts: &mut I) -> Result<T, ParseError> {
+    let field = parts.next().ok_or(ParseError::BadFormat)?;
+    str::from_utf8(field)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(ParseError::BadFormat)
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Put {
+        pri: u32,
+        delay: u32,
+        ttr: u32,
+        data: Vec<u8>,
+    },
+    Reserve,
+    ReserveWithTimeout {
+        timeout: u32,
+    },
+    Delete {
+        id: u64,
+    },
+    Release {
+        id: u64,
+        pri: u32,
+        delay: u32,
+    },
+    Watch {
+        tube: Vec<u8>,
+    },
+    UseTube {
+        tube: Vec<u8>,
+    },
+    ListTubes {},
+    StatsTube {
+        tube: Vec<u8>,
+    },
+    PeekReady {},
+    PeekDelayed {},
+    PeekBuried {},
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Incomplete,
+    UnknownCommand,
+    BadFormat,
+}
@@ -0,0 +1,168 @@
+use std::io;
+
+use kuska_handshake::async_std::{handshake_server, TokioCompatExt};
+use kuska_handshake::sodiumoxide::crypto::{auth, sign::ed25519};
+use kuska_handshake::{BoxStreamRecv, BoxStreamSend, Decrypted, KeyNonce, MSG_HEADER_LEN};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// This server's long-term Secret-Handshake identity, derived once at
+/// startup from `BEANSTALKDRS_SERVER_SEED`.
+pub struct ServerIdentity {
+    pk: ed25519::PublicKey,
+    sk: ed25519::SecretKey,
+}
+
+impl ServerIdentity {
+    pub fn from_seed(seed: &[u8; 32]) -> ServerIdentity {
+        let seed = ed25519::Seed::from_slice(seed).expect("seed is already 32 bytes");
+        let (pk, sk) = ed25519::keypair_from_seed(&seed);
+        ServerIdentity { pk, sk }
+    }
+}
+
+/// Network-wide shared secret plus this server's long-term identity,
+/// required to run the optional Secret-Handshake transport. Clients that
+/// don't know `network_key` can't even complete the handshake, let alone
+/// read a frame.
+pub struct SecureConfig {
+    pub network_key: [u8; 32],
+    pub identity: ServerIdentity,
+}
+
+/// Either a plain TCP connection or one wrapped in an authenticated,
+/// encrypted box-stream. `Server` only ever calls `read`/`write_all`, so
+/// the command layer above doesn't need to know which it got.
+pub enum Transport {
+    Plain(TcpStream),
+    Secure(BoxStream),
+}
+
+impl Transport {
+    /// Performs the handshake (if `config` is set) and returns the
+    /// resulting transport; falls back to plaintext when it's not.
+    pub async fn accept(stream: TcpStream, config: &Option<SecureConfig>) -> io::Result<Transport> {
+        match config {
+            None => Ok(Transport::Plain(stream)),
+            Some(config) => {
+                let net_id = auth::Key::from_slice(&config.network_key)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad network key"))?;
+
+                // `handshake_server` needs a futures-style AsyncRead/Write;
+                // `.wrap()` adapts the tokio `TcpStream` for the duration of
+                // the handshake, then hands the raw stream back for the
+                // box-stream, which drives its own tokio I/O.
+                let mut wrapped = stream.wrap();
+                let complete = handshake_server(
+                    &mut wrapped,
+                    net_id,
+                    config.identity.pk,
+                    config.identity.sk.clone(),
+                )
+                .await
+                .map_err(|err| io::Error::other(err.to_string()))?;
+                let stream = wrapped.into_inner();
+
+                let (key_nonce_send, key_nonce_recv) = KeyNonce::from_handshake(complete);
+                Ok(Transport::Secure(BoxStream::new(stream, key_nonce_send, key_nonce_recv)))
+            }
+        }
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf).await,
+            Transport::Secure(boxed) => boxed.read(buf).await,
+        }
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.write_all(buf).await,
+            Transport::Secure(boxed) => boxed.write_all(buf).await,
+        }
+    }
+}
+
+/// An authenticated, encrypted box-stream over a raw `TcpStream`, built on
+/// `kuska_handshake`'s transport-agnostic `BoxStreamSend`/`BoxStreamRecv`:
+/// they only encrypt/decrypt in-memory frames, so this struct is the part
+/// that actually reads/writes them over the socket.
+pub struct BoxStream {
+    stream: TcpStream,
+    send: BoxStreamSend,
+    recv: BoxStreamRecv,
+    leftover: Vec<u8>,
+}
+
+impl BoxStream {
+    fn new(stream: TcpStream, send_key_nonce: KeyNonce, recv_key_nonce: KeyNonce) -> BoxStream {
+        BoxStream {
+            stream,
+            send: BoxStreamSend::new(send_key_nonce),
+            recv: BoxStreamRecv::new(recv_key_nonce),
+            leftover: Vec::new(),
+        }
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.leftover.is_empty() {
+            let plaintext = self.read_frame().await?;
+            if plaintext.is_empty() {
+                return Ok(0);
+            }
+            self.leftover = plaintext;
+        }
+
+        let n = buf.len().min(self.leftover.len());
+        buf[..n].copy_from_slice(&self.leftover[..n]);
+        self.leftover.drain(..n);
+        Ok(n)
+    }
+
+    /// Pulls one full plaintext message out of the stream. `BoxStreamRecv`
+    /// processes a message in two steps (header, then body), each of
+    /// which tells us exactly how many more bytes to read next via
+    /// `recv_bytes()`.
+    async fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            let want = self.recv.recv_bytes();
+            if want == 0 {
+                return Ok(Vec::new()); // goodbye already received
+            }
+
+            let mut enc = vec![0u8; want];
+            self.stream.read_exact(&mut enc).await?;
+
+            let mut dec = vec![0u8; want];
+            match self.recv.decrypt(&enc, &mut dec) {
+                Ok(Decrypted::Goodbye) => return Ok(Vec::new()),
+                Ok(Decrypted::Some((_, written))) => {
+                    if written > 0 {
+                        dec.truncate(written);
+                        return Ok(dec);
+                    }
+                    // Header step: loop again to read the body it describes.
+                }
+                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+            }
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        for chunk in buf.chunks(kuska_handshake::MSG_BODY_MAX_LEN) {
+            self.write_frame(chunk).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_frame(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let mut enc = vec![0u8; MSG_HEADER_LEN + chunk.len()];
+        let (_, n_write) = self
+            .send
+            .encrypt(chunk, &mut enc)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        self.stream.write_all(&enc[..n_write]).await
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::jobqueue::{Job, JobId};
+
+/// How aggressively the log is flushed to disk. `Always` costs a
+/// `fsync` per write but survives a hard power loss; `Never` leaves it
+/// to the OS, which is only durable across process crashes, not power
+/// loss.
+#[derive(Clone, Copy)]
+pub enum SyncPolicy {
+    Always,
+    Never,
+}
+
+/// One state-changing operation, as recorded to (and replayed from) the
+/// log. `delay` on a `Put` is "seconds from when this record is
+/// applied", so replaying it (or a compacted snapshot of it) re-derives
+/// a fresh deadline rather than trying to persist a wall-clock instant.
+#[derive(Debug, Clone)]
+pub enum WalOp {
+    Put {
+        id: JobId,
+        tube: String,
+        pri: u32,
+        delay: u32,
+        ttr: u32,
+        data: Vec<u8>,
+    },
+    Reserve { id: JobId },
+    Delete { id: JobId },
+    Release { id: JobId, pri: u32, delay: u32 },
+}
+
+/// An append-only, length-framed log of `WalOp` records, with enough to
+/// replay it into a fresh `JobQueue` on startup and to compact it down
+/// to just the jobs still alive.
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+    sync_policy: SyncPolicy,
+}
+
+impl Wal {
+    pub fn open(path: &Path, sync_policy: SyncPolicy) -> io::Result<Wal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal {
+            file,
+            path: path.to_owned(),
+            sync_policy,
+        })
+    }
+
+    pub fn append(&mut self, op: &WalOp) -> io::Result<()> {
+        let body = encode(op);
+        self.file.write_all(&(body.len() as u32).to_be_bytes())?;
+        self.file.write_all(&body)?;
+
+        if let SyncPolicy::Always = self.sync_policy {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads every record out of `path` in order. Returns an empty list
+    /// for a fresh data directory (no log yet).
+    pub fn replay(path: &Path) -> io::Result<Vec<WalOp>> {
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut ops = Vec::new();
+
+        loop {
+            let mut len_bytes = [0u8; 4];
+            match reader.read_exact(&mut len_bytes) {
+                Ok(()) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+
+            let len = u32::from_be_bytes(len_bytes) as usize;
+            let mut body = vec![0u8; len];
+            reader.read_exact(&mut body)?;
+            ops.push(decode(&body)?);
+        }
+
+        Ok(ops)
+    }
+
+    /// Rewrites the log to hold a single `Put` per still-live job (with
+    /// `remaining_delay` substituted for the original `delay`), dropping
+    /// the history of deletes/releases that led there. Written to a temp
+    /// file and renamed into place so a crash mid-compaction leaves the
+    /// original log intact.
+    pub fn compact(&mut self, live: &[(Job, u32)]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("compact");
+        {
+            let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+            for (job, remaining_delay) in live {
+                let op = WalOp::Put {
+                    id: job.id,
+                    tube: job.tube.clone(),
+                    pri: job.pri,
+                    delay: *remaining_delay,
+                    ttr: job.ttr,
+                    data: job.data.clone(),
+                };
+                let body = encode(&op);
+                tmp.write_all(&(body.len() as u32).to_be_bytes())?;
+                tmp.write_all(&body)?;
+            }
+            tmp.flush()?;
+            tmp.get_ref().sync_data()?;
+        }
+
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn encode(op: &WalOp) -> Vec<u8> {
+    let mut out = Vec::new();
+    match op {
+        WalOp::Put { id, tube, pri, delay, ttr, data } => {
+            out.push(0);
+            out.extend_from_slice(&id.to_be_bytes());
+            out.extend_from_slice(&(tube.len() as u16).to_be_bytes());
+            out.extend_from_slice(tube.as_bytes());
+            out.extend_from_slice(&pri.to_be_bytes());
+            out.extend_from_slice(&delay.to_be_bytes());
+            out.extend_from_slice(&ttr.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(data);
+        }
+        WalOp::Reserve { id } => {
+            out.push(1);
+            out.extend_from_slice(&id.to_be_bytes());
+        }
+        WalOp::Delete { id } => {
+            out.push(2);
+            out.extend_from_slice(&id.to_be_bytes());
+        }
+        WalOp::Release { id, pri, delay } => {
+            out.push(3);
+            out.extend_from_slice(&id.to_be_bytes());
+            out.extend_from_slice(&pri.to_be_bytes());
+            out.extend_from_slice(&delay.to_be_bytes());
+        }
+    }
+    out
+}
+
+fn decode(body: &[u8]) -> io::Result<WalOp> {
+    fn corrupt() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "corrupt WAL record")
+    }
+
+    let (&tag, rest) = body.split_first().ok_or_else(corrupt)?;
+
+    match tag {
+        0 => {
+            let id = read_u64(rest, 0).ok_or_else(corrupt)?;
+            let tube_len = read_u16(rest, 8).ok_or_else(corrupt)? as usize;
+            let tube_start = 10;
+            let tube_end = tube_start + tube_len;
+            let tube = String::from_utf8(
+                rest.get(tube_start..tube_end).ok_or_else(corrupt)?.to_vec(),
+            )
+            .map_err(|_| corrupt())?;
+
+            let mut off = tube_end;
+            let pri = read_u32(rest, off).ok_or_else(corrupt)?;
+            off += 4;
+            let delay = read_u32(rest, off).ok_or_else(corrupt)?;
+            off += 4;
+            let ttr = read_u32(rest, off).ok_or_else(corrupt)?;
+            off += 4;
+            let data_len = read_u32(rest, off).ok_or_else(corrupt)? as usize;
+            off += 4;
+            let data = rest.get(off..off + data_len).ok_or_else(corrupt)?.to_vec();
+
+            Ok(WalOp::Put { id, tube, pri, delay, ttr, data })
+        }
+        1 => Ok(WalOp::Reserve { id: read_u64(rest, 0).ok_or_else(corrupt)? }),
+        2 => Ok(WalOp::Delete { id: read_u64(rest, 0).ok_or_else(corrupt)? }),
+        3 => {
+            let id = read_u64(rest, 0).ok_or_else(corrupt)?;
+            let pri = read_u32(rest, 8).ok_or_else(corrupt)?;
+            let delay = read_u32(rest, 12).ok_or_else(corrupt)?;
+            Ok(WalOp::Release { id, pri, delay })
+        }
+        _ => Err(corrupt()),
+    }
+}
+
+fn read_u64(buf: &[u8], off: usize) -> Option<u64> {
+    buf.get(off..off + 8).map(|s| {
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(s);
+        u64::from_be_bytes(arr)
+    })
+}
+
+fn read_u32(buf: &[u8], off: usize) -> Option<u32> {
+    buf.get(off..off + 4).map(|s| {
+        let mut arr = [0u8; 4];
+        arr.copy_from_slice(s);
+        u32::from_be_bytes(arr)
+    })
+}
+
+fn read_u16(buf: &[u8], off: usize) -> Option<u16> {
+    buf.get(off..off + 2).map(|s| {
+        let mut arr = [0u8; 2];
+        arr.copy_from_slice(s);
+        u16::from_be_bytes(arr)
+    })
+}
@@ -0,0 +1,174 @@
+//! Drives the actual `beanstalkdrs` binary over a real TCP socket and
+//! asserts on its raw protocol responses. Each test spawns its own server
+//! bound to an ephemeral port (`BEANSTALKDRS_ADDR=127.0.0.1:0`), so tests
+//! never fight each other (or anything else on the machine) over a fixed
+//! port.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// Spawns the compiled binary on an ephemeral port and kills it on drop.
+struct TestServer {
+    child: Child,
+    addr: String,
+}
+
+impl TestServer {
+    fn start() -> TestServer {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_beanstalkdrs"))
+            .env("BEANSTALKDRS_ADDR", "127.0.0.1:0")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start beanstalkdrs");
+
+        let stdout = child.stdout.take().expect("child stdout was piped");
+        let mut reader = BufReader::new(stdout);
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("failed to read startup line");
+
+        let addr = line
+            .trim()
+            .strip_prefix("listening on ")
+            .unwrap_or_else(|| panic!("unexpected startup output: {:?}", line))
+            .to_owned();
+
+        // The server keeps logging to stdout past this first line; if we
+        // dropped the reader here, the pipe's read end would close and
+        // the next `println!` in the server would panic on a broken pipe
+        // (taking down whatever connection task was mid-command). Drain
+        // it on a background thread for the rest of the test instead.
+        std::thread::spawn(move || {
+            let mut sink = String::new();
+            while reader.read_line(&mut sink).unwrap_or(0) > 0 {
+                sink.clear();
+            }
+        });
+
+        TestServer { child, addr }
+    }
+
+    fn connect(&self) -> Conn {
+        let stream = TcpStream::connect(&self.addr).expect("failed to connect to test server");
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        Conn { reader: BufReader::new(stream) }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A single connection's buffered reader kept alive for the whole test:
+/// a fresh `BufReader` per read would silently swallow whatever of the
+/// next reply it over-read into its internal buffer (e.g. a job's body
+/// arriving in the same packet as its `RESERVED` line), so every read
+/// against this connection goes through the same reader.
+struct Conn {
+    reader: BufReader<TcpStream>,
+}
+
+impl Conn {
+    fn send(&mut self, line: &str) {
+        self.reader.get_mut().write_all(line.as_bytes()).unwrap();
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) {
+        self.reader.read_exact(buf).unwrap();
+    }
+}
+
+#[test]
+fn put_then_reserve_then_delete() {
+    let server = TestServer::start();
+    let mut conn = server.connect();
+
+    conn.send("put 1 0 60 2\r\nhi\r\n");
+    let reply = conn.read_line();
+    assert!(reply.starts_with("INSERTED "), "unexpected reply: {:?}", reply);
+    let id = reply.trim().strip_prefix("INSERTED ").unwrap().to_owned();
+
+    conn.send("reserve\r\n");
+    let reply = conn.read_line();
+    assert_eq!(reply.trim(), format!("RESERVED {id} 2"));
+    let mut body = [0u8; 4]; // "hi" + trailing "\r\n"
+    conn.read_exact(&mut body);
+    assert_eq!(&body[..2], b"hi");
+
+    conn.send(&format!("delete {id}\r\n"));
+    let reply = conn.read_line();
+    assert_eq!(reply.trim(), "DELETED");
+}
+
+#[test]
+fn put_then_delete_without_reserving() {
+    let server = TestServer::start();
+    let mut conn = server.connect();
+
+    conn.send("put 1 0 60 2\r\nhi\r\n");
+    let reply = conn.read_line();
+    assert!(reply.starts_with("INSERTED "), "unexpected reply: {:?}", reply);
+    let id = reply.trim().strip_prefix("INSERTED ").unwrap().to_owned();
+
+    conn.send(&format!("delete {id}\r\n"));
+    let reply = conn.read_line();
+    assert_eq!(reply.trim(), "DELETED");
+
+    // Deleting it again answers NOT_FOUND, not a second DELETED.
+    conn.send(&format!("delete {id}\r\n"));
+    let reply = conn.read_line();
+    assert_eq!(reply.trim(), "NOT FOUND");
+}
+
+#[test]
+fn watch_with_invalid_utf8_tube_gets_bad_format() {
+    let server = TestServer::start();
+    let mut conn = server.connect();
+
+    let mut line = b"watch ".to_vec();
+    line.extend_from_slice(&[0xff, 0xfe]);
+    line.extend_from_slice(b"\r\n");
+    conn.reader.get_mut().write_all(&line).unwrap();
+
+    let reply = conn.read_line();
+    assert_eq!(reply.trim(), "BAD_FORMAT");
+}
+
+#[test]
+fn stats_tube_reports_the_requested_tube() {
+    let server = TestServer::start();
+    let mut conn = server.connect();
+
+    conn.send("use widgets\r\n");
+    let reply = conn.read_line();
+    assert_eq!(reply.trim(), "USING widgets");
+
+    conn.send("put 1 0 60 2\r\nhi\r\n");
+    let reply = conn.read_line();
+    assert!(reply.starts_with("INSERTED "), "unexpected reply: {:?}", reply);
+
+    conn.send("stats-tube widgets\r\n");
+    let reply = conn.read_line();
+    assert!(reply.starts_with("OK "), "unexpected reply: {:?}", reply);
+    let len: usize = reply.trim().strip_prefix("OK ").unwrap().parse().unwrap();
+    let mut body = vec![0u8; len + 2]; // + trailing "\r\n" after the body
+    conn.read_exact(&mut body);
+    let body = String::from_utf8(body[..len].to_vec()).unwrap();
+    assert!(body.contains("name: widgets"), "body was: {:?}", body);
+    assert!(body.contains("current-jobs-ready: 1"), "body was: {:?}", body);
+
+    conn.send("stats-tube never-used\r\n");
+    let reply = conn.read_line();
+    assert_eq!(reply.trim(), "NOT_FOUND");
+}